@@ -7,15 +7,22 @@ use axum::{
     Router,
 };
 use clap::{Parser, Subcommand};
-use futures::TryStreamExt;
+use nix_compat::narinfo::VerifyingKey;
 use nix_compat::nar::reader::r#async as nar_reader;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::io::{self, AsyncRead, AsyncReadExt};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{self, AsyncRead, AsyncReadExt, ReadBuf};
 use tokio::sync::mpsc;
 use tracing::{debug, error, info, instrument};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
+mod store;
+
 static BUFFER_SIZE: usize = 8192;
 
 #[derive(Parser, Debug)]
@@ -27,7 +34,19 @@ struct Cli {
 
 #[derive(Subcommand, Debug)]
 enum Command {
-    Serve { store_uri: String },
+    Serve {
+        store_uri: String,
+
+        /// A trusted ed25519 public key, in Nix's `name:base64` format. May
+        /// be passed multiple times. narinfo signatures are checked against
+        /// this set unless `--insecure` is passed.
+        #[arg(long = "trusted-public-key", value_name = "NAME:KEY")]
+        trusted_public_keys: Vec<String>,
+
+        /// Skip NarHash and narinfo signature verification entirely.
+        #[arg(long)]
+        insecure: bool,
+    },
 }
 
 #[tokio::main]
@@ -40,18 +59,49 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
 
     match cli.command {
-        Command::Serve { store_uri } => serve(store_uri).await,
+        Command::Serve {
+            store_uri,
+            trusted_public_keys,
+            insecure,
+        } => serve(store_uri, trusted_public_keys, insecure).await,
     }
 }
 
+/// Server-wide state shared across requests.
+struct ServeState {
+    backend: Arc<dyn store::StoreBackend>,
+    trusted_keys: Vec<VerifyingKey>,
+    insecure: bool,
+}
+
 #[instrument]
-async fn serve(store_uri: String) -> Result<()> {
+async fn serve(store_uri: String, trusted_public_keys: Vec<String>, insecure: bool) -> Result<()> {
     let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
-    let store_uri = Arc::new(store_uri);
+
+    let backend = Arc::from(store::from_addr(&store_uri).await?);
+
+    let trusted_keys = trusted_public_keys
+        .iter()
+        .map(|s| {
+            VerifyingKey::parse(s)
+                .map(|(_, key)| key)
+                .map_err(|e| anyhow::anyhow!("invalid --trusted-public-key {s:?}: {e}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if trusted_keys.is_empty() && !insecure {
+        info!("No --trusted-public-key configured; all responses will be rejected unless --insecure is passed");
+    }
+
+    let state = Arc::new(ServeState {
+        backend,
+        trusted_keys,
+        insecure,
+    });
 
     let app = Router::new()
         .route("/*path", get(handle_request))
-        .with_state(store_uri);
+        .with_state(state);
 
     info!("Listening on http://{}", addr);
 
@@ -61,42 +111,85 @@ async fn serve(store_uri: String) -> Result<()> {
     Ok(())
 }
 
-#[instrument]
+/// Which representation to render a directory listing in, chosen from the
+/// request's `Accept` header.
+#[derive(Debug, Clone, Copy)]
+enum ListingFormat {
+    Json,
+    Html,
+}
+
+#[instrument(skip(state))]
 async fn handle_request(
     Path(path): Path<String>,
-    axum::extract::State(store_uri): axum::extract::State<Arc<String>>,
+    headers: axum::http::HeaderMap,
+    axum::extract::State(state): axum::extract::State<Arc<ServeState>>,
 ) -> impl IntoResponse {
+    let format = match headers
+        .get(axum::http::header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(accept) if accept.contains("application/json") => ListingFormat::Json,
+        _ => ListingFormat::Html,
+    };
     match NixStorePath::parse(&path) {
         None => (StatusCode::NOT_FOUND, "Not found").into_response(),
         Some(store_path) => {
-            let uri = format!("{}/{}.narinfo", store_uri, store_path.hash);
-            info!("Fetching narinfo from {}", uri);
-            let raw_narinfo = reqwest::get(uri).await.unwrap().text().await.unwrap();
-            let narinfo = nix_compat::narinfo::NarInfo::parse(&raw_narinfo).unwrap();
-
-            let nar_path = narinfo.url;
-            let nar_url = format!("{}/{}", store_uri, nar_path);
-            info!("Redirecting to {}", nar_url);
-
-            let client = reqwest::Client::new();
-            let nar_resp = match client.get(&nar_url).send().await {
-                Ok(resp) => resp,
-                Err(_) => {
-                    return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to fetch NAR")
-                        .into_response()
+            info!("Fetching narinfo for {}", store_path.hash);
+            let raw_narinfo = match state.backend.fetch_narinfo(&store_path.hash).await {
+                Ok(text) => text,
+                Err(e) => {
+                    error!(e=%e, "Failed to fetch narinfo");
+                    return (StatusCode::BAD_GATEWAY, "Failed to fetch narinfo").into_response();
+                }
+            };
+            let narinfo = match nix_compat::narinfo::NarInfo::parse(&raw_narinfo) {
+                Ok(n) => n,
+                Err(e) => {
+                    error!(e=%e, "Failed to parse narinfo");
+                    return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to parse narinfo")
+                        .into_response();
                 }
             };
 
-            if !nar_resp.status().is_success() {
-                return (StatusCode::BAD_GATEWAY, "Failed to fetch NAR").into_response();
+            if !state.insecure {
+                let verified = !state.trusted_keys.is_empty()
+                    && narinfo
+                        .signatures
+                        .iter()
+                        .any(|sig| state.trusted_keys.iter().any(|key| key.verify(&narinfo, sig)));
+
+                if !verified {
+                    error!(hash = %store_path.hash, "narinfo signature verification failed");
+                    return (StatusCode::FORBIDDEN, "Signature verification failed")
+                        .into_response();
+                }
             }
 
-            let s = nar_resp.bytes_stream().map_err(|e| {
-                let e = e.without_url();
-                error!(e=%e, "Failed to get NAR body");
-                io::Error::new(io::ErrorKind::BrokenPipe, e.to_string())
-            });
-            let r = tokio_util::io::StreamReader::new(s);
+            // The `.ls` + Range fast path fetches raw bytes directly, so it
+            // can't recompute NarHash over the full NAR the way the regular
+            // path does. It still relies on the narinfo signature check
+            // above (which covers the signed NarHash field) as its
+            // integrity boundary, same as the rest of this branch when
+            // `--insecure` is passed; it doesn't add a second, independent
+            // content verification on top of that.
+            if narinfo.compression.is_none() {
+                match fetch_range(state.backend.as_ref(), narinfo.url, &store_path).await {
+                    Ok(Some(resp)) => return resp,
+                    Ok(None) => {}
+                    Err(e) => error!(e=%e, "Listing-based range fetch failed, falling back"),
+                }
+            }
+
+            info!("Fetching NAR from {}", narinfo.url);
+
+            let r = match state.backend.open_nar(narinfo.url, None).await {
+                Ok(r) => io::BufReader::new(r),
+                Err(e) => {
+                    error!(e=%e, "Failed to fetch NAR");
+                    return (StatusCode::BAD_GATEWAY, "Failed to fetch NAR").into_response();
+                }
+            };
 
             let r: Box<dyn AsyncRead + Send + Unpin> = match narinfo.compression {
                 None => Box::new(r),
@@ -113,50 +206,515 @@ async fn handle_request(
                 }
             };
 
+            let hasher = Arc::new(Mutex::new(Sha256::new()));
+            let buffer = Arc::new(Mutex::new(Vec::new()));
+            let r = HashingReader {
+                inner: r,
+                hasher: hasher.clone(),
+                buffer: buffer.clone(),
+            };
+
             let mut r = io::BufReader::new(r);
 
             let (tx, rx) = mpsc::channel(BUFFER_SIZE);
 
             let stream = tokio_stream::wrappers::ReceiverStream::new(rx);
 
-            let target_path = store_path
-                .file_path
-                .map(|s| format!("/{}", s))
-                .unwrap_or("/".to_string());
+            let target_path = store_path.file_path.clone().unwrap_or_default();
+            let expected_nar_hash = narinfo.nar_hash;
+            let hash = store_path.hash.clone();
+            let state = state.clone();
+
+            let (content_type_tx, content_type_rx) = tokio::sync::oneshot::channel();
 
             info!("Searching for: {:?}", target_path);
 
             tokio::spawn(async move {
                 let root_node = nar_reader::open(&mut r).await.unwrap();
 
-                if let Err(err) = search_nar(root_node, target_path, tx).await {
-                    error!(e=%err, "Failed to search NAR");
+                let outcome = match search_nar(
+                    root_node,
+                    target_path.clone(),
+                    tx.clone(),
+                    format,
+                    Some(content_type_tx),
+                )
+                .await
+                {
+                    Ok(outcome) => outcome,
+                    Err(err) => {
+                        error!(e=%err, "Failed to search NAR");
+                        return;
+                    }
+                };
+
+                if !state.insecure {
+                    let digest = hasher.lock().unwrap().clone().finalize();
+                    if digest.as_slice() != expected_nar_hash.as_ref() {
+                        error!(%hash, "NAR hash mismatch, aborting stream");
+                        let _ = tx
+                            .send(Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "NAR hash mismatch",
+                            )))
+                            .await;
+                        return;
+                    }
+                }
+
+                if let SearchOutcome::Symlink(link_target) = outcome {
+                    let nar_bytes = Arc::new(std::mem::take(&mut *buffer.lock().unwrap()));
+                    if let Err(err) = resolve_symlink(
+                        state, nar_bytes, hash, target_path, link_target, format, tx.clone(), 1,
+                    )
+                    .await
+                    {
+                        error!(e=%err, "Failed to follow symlink");
+                        let _ = tx
+                            .send(Err(io::Error::new(io::ErrorKind::Other, err.to_string())))
+                            .await;
+                    }
                 }
             });
 
             info!("Streaming response");
 
+            // The search task tells us as soon as it knows whether it's
+            // about to stream a file (no fixed content type) or send a
+            // directory listing (json/html), well before the body is fully
+            // produced, so the header can still be set here.
+            let content_type = content_type_rx.await.ok().flatten();
+
+            let mut builder = Response::builder().status(StatusCode::OK);
+            if let Some(content_type) = content_type {
+                builder = builder.header(axum::http::header::CONTENT_TYPE, content_type);
+            }
+
+            builder.body(axum::body::Body::from_stream(stream)).unwrap()
+        }
+    }
+}
+
+/// A `<hash>.ls` NAR listing, as published alongside a NAR by caches built
+/// with `write-nar-listing=1`.
+#[derive(Debug, Deserialize)]
+struct NarListing {
+    #[allow(dead_code)]
+    version: u32,
+    root: ListingNode,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ListingNode {
+    Regular {
+        size: u64,
+        #[serde(rename = "narOffset")]
+        nar_offset: u64,
+    },
+    Directory {
+        entries: HashMap<String, ListingNode>,
+    },
+    Symlink {
+        target: String,
+    },
+}
+
+/// Walks a `.ls` listing following `path` (no leading slash), returning the
+/// node at that path if one exists.
+fn walk_listing<'a>(mut node: &'a ListingNode, path: &str) -> Option<&'a ListingNode> {
+    if path.is_empty() {
+        return Some(node);
+    }
+    for component in path.split('/') {
+        match node {
+            ListingNode::Directory { entries } => node = entries.get(component)?,
+            _ => return None,
+        }
+    }
+    Some(node)
+}
+
+/// Tries to serve `store_path` directly out of the `.ls` listing via a Range
+/// request against the (uncompressed) NAR, skipping the NAR parser entirely.
+/// Returns `Ok(None)` to signal that the caller should fall back to the
+/// regular streaming path (no listing, the target isn't a regular file, or
+/// the `.ls` fetch 404s).
+async fn fetch_range(
+    backend: &dyn store::StoreBackend,
+    nar_path: &str,
+    store_path: &NixStorePath,
+) -> Result<Option<Response>> {
+    let listing = match backend.fetch_listing(&store_path.hash).await? {
+        Some(text) => text,
+        None => return Ok(None),
+    };
+
+    let listing: NarListing = serde_json::from_str(&listing)?;
+    let target = store_path.file_path.as_deref().unwrap_or("");
+
+    let (size, nar_offset) = match walk_listing(&listing.root, target) {
+        Some(ListingNode::Regular { size, nar_offset }) => (*size, *nar_offset),
+        _ => return Ok(None),
+    };
+
+    if size == 0 {
+        return Ok(Some(
             Response::builder()
                 .status(StatusCode::OK)
-                .body(axum::body::Body::from_stream(stream))
-                .unwrap()
+                .body(axum::body::Body::empty())
+                .unwrap(),
+        ));
+    }
+
+    info!(
+        "Fast path: {} bytes={}-{}",
+        nar_path,
+        nar_offset,
+        nar_offset + size - 1
+    );
+
+    let reader = backend
+        .open_nar(nar_path, Some((nar_offset, nar_offset + size - 1)))
+        .await?;
+
+    let stream = tokio_util::io::ReaderStream::new(reader);
+
+    Ok(Some(
+        Response::builder()
+            .status(StatusCode::OK)
+            .body(axum::body::Body::from_stream(stream))
+            .unwrap(),
+    ))
+}
+
+/// Maximum number of symlinks `resolve_symlink` will follow before giving up,
+/// to guard against symlink loops.
+const MAX_SYMLINK_HOPS: u32 = 16;
+
+/// Resolves a symlink found while searching a NAR and continues the search
+/// from there: restarting at the root of the same (already-fetched,
+/// already-verified) NAR for a relative target via `nar_bytes`, or by
+/// fetching another store path's narinfo/NAR for an absolute
+/// `/nix/store/...` target.
+#[allow(clippy::too_many_arguments)]
+async fn resolve_symlink(
+    state: Arc<ServeState>,
+    nar_bytes: Arc<Vec<u8>>,
+    current_hash: String,
+    current_target_path: String,
+    link_target: String,
+    format: ListingFormat,
+    tx: mpsc::Sender<std::result::Result<Vec<u8>, std::io::Error>>,
+    hops: u32,
+) -> Result<()> {
+    if link_target.starts_with("/nix/store/") {
+        let store_path = NixStorePath::parse(&link_target)
+            .ok_or_else(|| anyhow::anyhow!("failed to parse symlink target {link_target:?}"))?;
+        let remainder = store_path.file_path.unwrap_or_default();
+
+        info!(
+            "Following symlink across store paths: {}/{} -> {}/{}",
+            current_hash, current_target_path, store_path.hash, remainder
+        );
+
+        return Box::pin(resolve_and_stream(
+            state,
+            store_path.hash,
+            remainder,
+            format,
+            tx,
+            hops,
+        ))
+        .await;
+    }
+
+    if link_target.starts_with('/') {
+        anyhow::bail!("cannot follow symlink outside the Nix store: {link_target:?}");
+    }
+
+    let joined = normalize_relative_path(&parent_dir(&current_target_path), &link_target);
+
+    info!(
+        "Following relative symlink within the same NAR: {}/{} -> {}",
+        current_hash, current_target_path, joined
+    );
+
+    Box::pin(search_in_memory(
+        state,
+        nar_bytes,
+        current_hash,
+        joined,
+        format,
+        tx,
+        hops,
+    ))
+    .await
+}
+
+/// Restarts a search at the root of a NAR that's already been fetched and
+/// hash-verified (kept around as `nar_bytes` by `HashingReader`), so a
+/// relative symlink hop within that NAR never re-fetches it. Recurses
+/// through `resolve_symlink` on a further hop, same as `resolve_and_stream`.
+async fn search_in_memory(
+    state: Arc<ServeState>,
+    nar_bytes: Arc<Vec<u8>>,
+    hash: String,
+    target_path: String,
+    format: ListingFormat,
+    tx: mpsc::Sender<std::result::Result<Vec<u8>, std::io::Error>>,
+    hops: u32,
+) -> Result<()> {
+    if hops > MAX_SYMLINK_HOPS {
+        anyhow::bail!("too many symlink hops resolving {hash}/{target_path}");
+    }
+
+    let mut r = io::BufReader::new(BufferReader {
+        data: nar_bytes.clone(),
+        pos: 0,
+    });
+
+    let root_node = nar_reader::open(&mut r).await?;
+    let outcome =
+        Box::pin(search_nar(root_node, target_path.clone(), tx.clone(), format, None)).await?;
+
+    match outcome {
+        SearchOutcome::Done => Ok(()),
+        SearchOutcome::Symlink(link_target) => {
+            resolve_symlink(
+                state,
+                nar_bytes,
+                hash,
+                target_path,
+                link_target,
+                format,
+                tx,
+                hops + 1,
+            )
+            .await
+        }
+    }
+}
+
+/// Fetches and verifies `hash`'s narinfo, opens its NAR, and searches it for
+/// `target_path`, recursing through `resolve_symlink` on symlink hops.
+/// Unlike `handle_request`'s first lookup, failures here can't be reported as
+/// an HTTP status (the response is already streaming); they're returned to
+/// `handle_request`, which sends an error down the `tx` channel so the
+/// connection is dropped instead of ending in a clean EOF.
+async fn resolve_and_stream(
+    state: Arc<ServeState>,
+    hash: String,
+    target_path: String,
+    format: ListingFormat,
+    tx: mpsc::Sender<std::result::Result<Vec<u8>, std::io::Error>>,
+    hops: u32,
+) -> Result<()> {
+    if hops > MAX_SYMLINK_HOPS {
+        anyhow::bail!("too many symlink hops resolving {hash}/{target_path}");
+    }
+
+    let raw_narinfo = state.backend.fetch_narinfo(&hash).await?;
+    let narinfo = nix_compat::narinfo::NarInfo::parse(&raw_narinfo)
+        .map_err(|e| anyhow::anyhow!("failed to parse narinfo for {hash}: {e}"))?;
+
+    if !state.insecure {
+        let verified = !state.trusted_keys.is_empty()
+            && narinfo
+                .signatures
+                .iter()
+                .any(|sig| state.trusted_keys.iter().any(|key| key.verify(&narinfo, sig)));
+        if !verified {
+            anyhow::bail!("narinfo signature verification failed for {hash}");
+        }
+    }
+
+    let r = state.backend.open_nar(narinfo.url, None).await?;
+    let r = io::BufReader::new(r);
+
+    let r: Box<dyn AsyncRead + Send + Unpin> = match narinfo.compression {
+        None => Box::new(r),
+        Some("bzip2") => Box::new(async_compression::tokio::bufread::BzDecoder::new(r)),
+        Some("gzip") => Box::new(async_compression::tokio::bufread::GzipDecoder::new(r)),
+        Some("xz") => Box::new(async_compression::tokio::bufread::XzDecoder::new(r)),
+        Some("zstd") => Box::new(async_compression::tokio::bufread::ZstdDecoder::new(r)),
+        Some(comp_str) => anyhow::bail!("unsupported compression: {comp_str}"),
+    };
+
+    let hasher = Arc::new(Mutex::new(Sha256::new()));
+    let buffer = Arc::new(Mutex::new(Vec::new()));
+    let r = HashingReader {
+        inner: r,
+        hasher: hasher.clone(),
+        buffer: buffer.clone(),
+    };
+    let mut r = io::BufReader::new(r);
+
+    let root_node = nar_reader::open(&mut r).await?;
+    let outcome = Box::pin(search_nar(
+        root_node,
+        target_path.clone(),
+        tx.clone(),
+        format,
+        None,
+    ))
+    .await?;
+
+    if !state.insecure {
+        let digest = hasher.lock().unwrap().clone().finalize();
+        if digest.as_slice() != narinfo.nar_hash.as_ref() {
+            anyhow::bail!("NAR hash mismatch for {hash}");
+        }
+    }
+
+    match outcome {
+        SearchOutcome::Done => Ok(()),
+        SearchOutcome::Symlink(link_target) => {
+            let nar_bytes = Arc::new(std::mem::take(&mut *buffer.lock().unwrap()));
+            resolve_symlink(
+                state,
+                nar_bytes,
+                hash,
+                target_path,
+                link_target,
+                format,
+                tx,
+                hops + 1,
+            )
+            .await
+        }
+    }
+}
+
+fn parent_dir(path: &str) -> String {
+    match path.rsplit_once('/') {
+        Some((dir, _)) => dir.to_string(),
+        None => String::new(),
+    }
+}
+
+/// Joins `target` onto `base_dir` and resolves any `.`/`..` components,
+/// keeping the result within the current NAR (it never grows below the
+/// root).
+fn normalize_relative_path(base_dir: &str, target: &str) -> String {
+    let mut components: Vec<&str> = if base_dir.is_empty() {
+        Vec::new()
+    } else {
+        base_dir.split('/').collect()
+    };
+
+    for component in target.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            other => components.push(other),
         }
     }
+
+    components.join("/")
 }
 
-// TODO: support symlinks pointing to other NARs
-// Support directories
-#[instrument(skip(node, tx))]
+/// Wraps an `AsyncRead`, feeding every byte that passes through into both a
+/// running SHA-256 (so the full decompressed NAR can be checked against
+/// `NarHash` once streaming completes) and an in-memory buffer of the whole
+/// NAR (so a same-NAR relative symlink hop can restart the walk from the
+/// root via `BufferReader` instead of re-fetching it).
+struct HashingReader<R> {
+    inner: R,
+    hasher: Arc<Mutex<Sha256>>,
+    buffer: Arc<Mutex<Vec<u8>>>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if poll.is_ready() {
+            let data = &buf.filled()[before..];
+            if !data.is_empty() {
+                self.hasher.lock().unwrap().update(data);
+                self.buffer.lock().unwrap().extend_from_slice(data);
+            }
+        }
+        poll
+    }
+}
+
+/// An `AsyncRead` over an already-fetched, already-verified NAR held in
+/// memory, used to restart a search from the root without going back to the
+/// backend.
+struct BufferReader {
+    data: Arc<Vec<u8>>,
+    pos: usize,
+}
+
+impl AsyncRead for BufferReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let remaining = &this.data[this.pos..];
+        let n = remaining.len().min(buf.remaining());
+        buf.put_slice(&remaining[..n]);
+        this.pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The result of walking a NAR looking for `target_path`.
+enum SearchOutcome {
+    /// The target was a file (streamed) or a directory (listing sent).
+    Done,
+    /// The target resolved to a symlink whose raw target text is given here;
+    /// the caller is responsible for resolving it (possibly in another NAR)
+    /// and restarting the search.
+    Symlink(String),
+}
+
+/// Picks the HTTP `Content-Type` for a directory listing response.
+fn listing_content_type(format: ListingFormat) -> &'static str {
+    match format {
+        ListingFormat::Json => "application/json",
+        ListingFormat::Html => "text/html; charset=utf-8",
+    }
+}
+
+#[instrument(skip(node, tx, content_type_tx))]
 async fn search_nar<'a, 'r: 'a>(
     node: nar_reader::Node<'a, 'r>,
     target_path: String,
     tx: mpsc::Sender<std::result::Result<Vec<u8>, std::io::Error>>,
-) -> Result<()> {
+    format: ListingFormat,
+    content_type_tx: Option<tokio::sync::oneshot::Sender<Option<&'static str>>>,
+) -> Result<SearchOutcome> {
     Ok(match node {
         nar_reader::Node::File { reader, .. } => {
+            if let Some(content_type_tx) = content_type_tx {
+                let _ = content_type_tx.send(None);
+            }
             stream_file(reader, tx.clone(), true).await;
+            SearchOutcome::Done
         }
-        nar_reader::Node::Directory(mut dir_reader) => {
+        nar_reader::Node::Symlink { target } if target_path.is_empty() => {
+            SearchOutcome::Symlink(symlink_target_to_string(&target))
+        }
+        nar_reader::Node::Directory(dir_reader) => {
+            if target_path.is_empty() {
+                if let Some(content_type_tx) = content_type_tx {
+                    let _ = content_type_tx.send(Some(listing_content_type(format)));
+                }
+                send_directory_listing(dir_reader, format, tx).await?;
+                return Ok(SearchOutcome::Done);
+            }
+
             let (dir_name, remaining_path) = match target_path.split_once('/') {
                 Some((dir, rest)) => (dir.to_string(), rest.to_string()),
                 None => (target_path, String::new()),
@@ -164,29 +722,197 @@ async fn search_nar<'a, 'r: 'a>(
 
             debug!("Searching directory: {}", dir_name);
 
+            let mut dir_reader = dir_reader;
+            let mut outcome = SearchOutcome::Done;
+            let mut content_type_tx = content_type_tx;
             while let Some(entry) = dir_reader.next().await? {
                 debug!("Entry: {:?}", std::str::from_utf8(&entry.name).unwrap());
+                let matches = entry.name == dir_name.as_bytes();
+
                 match entry.node {
                     nar_reader::Node::File { reader, .. } => {
-                        stream_file(reader, tx.clone(), entry.name == remaining_path.as_bytes())
-                            .await;
+                        let is_target = matches && remaining_path.is_empty();
+                        if is_target {
+                            if let Some(content_type_tx) = content_type_tx.take() {
+                                let _ = content_type_tx.send(None);
+                            }
+                        }
+                        stream_file(reader, tx.clone(), is_target).await;
                     }
-                    nar_reader::Node::Directory(_) => {
-                        Box::pin(search_nar(
+                    nar_reader::Node::Directory(_) if matches => {
+                        match Box::pin(search_nar(
                             entry.node,
-                            remaining_path.to_string(),
+                            remaining_path.clone(),
                             tx.clone(),
+                            format,
+                            content_type_tx.take(),
                         ))
-                        .await?;
+                        .await?
+                        {
+                            SearchOutcome::Done => {}
+                            // Don't `break` here: the NAR is a sequential
+                            // stream, so the remaining sibling entries still
+                            // need to be read (by this same loop) to keep
+                            // the hasher in sync with the full NAR, even
+                            // though we already know the outcome.
+                            found @ SearchOutcome::Symlink(_) => {
+                                outcome = found;
+                            }
+                        }
+                    }
+                    nar_reader::Node::Directory(_) => {
+                        // Not the entry we're after, but the NAR is a
+                        // sequential stream: fully drain it before letting
+                        // `next()` advance past it.
+                        Box::pin(drain_subtree(entry.node)).await?;
+                    }
+                    nar_reader::Node::Symlink { target } if matches && remaining_path.is_empty() => {
+                        outcome = SearchOutcome::Symlink(symlink_target_to_string(&target));
                     }
                     _ => (),
                 }
             }
+            outcome
         }
-        _ => (),
+        _ => SearchOutcome::Done,
     })
 }
 
+/// Fully reads a subtree's file contents without forwarding them anywhere,
+/// to keep the sequential NAR reader's cursor in sync when skipping past an
+/// entry that doesn't match the path being searched for.
+async fn drain_subtree<'a, 'r: 'a>(node: nar_reader::Node<'a, 'r>) -> Result<()> {
+    match node {
+        nar_reader::Node::File { reader, .. } => drain_file(reader).await,
+        nar_reader::Node::Directory(mut dir_reader) => {
+            while let Some(entry) = dir_reader.next().await? {
+                Box::pin(drain_subtree(entry.node)).await?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+async fn drain_file(mut reader: nar_reader::FileReader<'_, '_>) {
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    loop {
+        match reader.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                error!(e=%e, "Failed to drain file");
+                break;
+            }
+        }
+    }
+}
+
+fn symlink_target_to_string(target: &[u8]) -> String {
+    String::from_utf8_lossy(target).into_owned()
+}
+
+/// A single entry in a directory listing response.
+#[derive(Debug, serde::Serialize)]
+struct DirEntry {
+    name: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    executable: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+}
+
+/// Collects the immediate entries of a directory node and sends a single
+/// buffered listing body (JSON or HTML, per `format`) down the channel,
+/// instead of streaming file bytes.
+///
+/// Like `drain_subtree`, this must fully read each child (a file's contents,
+/// a subdirectory's whole tree) before calling `next()` again: the NAR is a
+/// sequential stream, and the listing only reports metadata, but the bytes
+/// still have to be consumed to keep the reader's cursor in sync and to
+/// leave the rest of the NAR intact for NarHash verification.
+async fn send_directory_listing(
+    mut dir_reader: nar_reader::DirReader<'_, '_>,
+    format: ListingFormat,
+    tx: mpsc::Sender<std::result::Result<Vec<u8>, std::io::Error>>,
+) -> Result<()> {
+    let mut entries = Vec::new();
+
+    while let Some(entry) = dir_reader.next().await? {
+        let name = String::from_utf8_lossy(&entry.name).into_owned();
+        let dir_entry = match entry.node {
+            nar_reader::Node::File {
+                executable,
+                size,
+                reader,
+            } => {
+                drain_file(reader).await;
+                DirEntry {
+                    name,
+                    kind: "file",
+                    size: Some(size),
+                    executable: Some(executable),
+                    target: None,
+                }
+            }
+            nar_reader::Node::Directory(dir_reader) => {
+                Box::pin(drain_subtree(nar_reader::Node::Directory(dir_reader))).await?;
+                DirEntry {
+                    name,
+                    kind: "directory",
+                    size: None,
+                    executable: None,
+                    target: None,
+                }
+            }
+            nar_reader::Node::Symlink { target } => DirEntry {
+                name,
+                kind: "symlink",
+                size: None,
+                executable: None,
+                target: Some(String::from_utf8_lossy(&target).into_owned()),
+            },
+        };
+        entries.push(dir_entry);
+    }
+
+    let body = match format {
+        ListingFormat::Json => serde_json::to_vec(&entries)?,
+        ListingFormat::Html => render_html_index(&entries).into_bytes(),
+    };
+
+    let _ = tx.send(Ok(body)).await;
+    Ok(())
+}
+
+/// Escapes text for safe inclusion in HTML markup (both attribute values and
+/// element content, which is all `render_html_index` needs).
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn render_html_index(entries: &[DirEntry]) -> String {
+    let mut html = String::from("<html><body><ul>\n");
+    for entry in entries {
+        let suffix = if entry.kind == "directory" { "/" } else { "" };
+        let name = escape_html(&entry.name);
+        html.push_str(&format!(
+            "<li><a href=\"{0}{1}\">{0}{1}</a></li>\n",
+            name, suffix
+        ));
+    }
+    html.push_str("</ul></body></html>\n");
+    html
+}
+
 #[instrument(skip(reader, tx, should_stream))]
 async fn stream_file(
     mut reader: nar_reader::FileReader<'_, '_>,
@@ -312,4 +1038,45 @@ mod test {
             Some("nix-2.26.0pre19700101_838d3c1-aarch64-darwin.tar.xz".to_string())
         );
     }
+
+    #[test]
+    fn test_walk_listing_regular_file() {
+        let listing: NarListing = serde_json::from_str(
+            r#"{"version":1,"root":{"type":"directory","entries":{"bin":{"type":"directory","entries":{"foo":{"type":"regular","size":42,"narOffset":128}}}}}}"#,
+        )
+        .unwrap();
+
+        match walk_listing(&listing.root, "bin/foo") {
+            Some(ListingNode::Regular { size, nar_offset }) => {
+                assert_eq!(*size, 42);
+                assert_eq!(*nar_offset, 128);
+            }
+            other => panic!("expected regular file node, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_walk_listing_missing_path() {
+        let listing: NarListing = serde_json::from_str(
+            r#"{"version":1,"root":{"type":"directory","entries":{}}}"#,
+        )
+        .unwrap();
+
+        assert!(walk_listing(&listing.root, "bin/foo").is_none());
+    }
+
+    #[test]
+    fn test_normalize_relative_path_sibling() {
+        assert_eq!(normalize_relative_path("bin", "../lib/libfoo.so"), "lib/libfoo.so");
+    }
+
+    #[test]
+    fn test_normalize_relative_path_same_dir() {
+        assert_eq!(normalize_relative_path("bin", "./foo"), "bin/foo");
+    }
+
+    #[test]
+    fn test_normalize_relative_path_from_root() {
+        assert_eq!(normalize_relative_path("", "bin/foo"), "bin/foo");
+    }
 }
@@ -0,0 +1,248 @@
+//! Store backends abstract over where narinfos and NARs actually live, so
+//! the streaming/decompression pipeline in `handle_request` doesn't need to
+//! know whether it's talking to an HTTP binary cache, an S3 bucket, or a
+//! local directory.
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use tokio::io::AsyncRead;
+
+/// A byte range request, as `(start, end)` inclusive, matching the semantics
+/// of an HTTP `Range: bytes=start-end` header.
+pub type ByteRange = (u64, u64);
+
+#[async_trait]
+pub trait StoreBackend: Send + Sync {
+    /// Fetches the raw `<hash>.narinfo` text for `hash`.
+    async fn fetch_narinfo(&self, hash: &str) -> Result<String>;
+
+    /// Fetches the raw `<hash>.ls` listing for `hash`, or `None` if the
+    /// cache doesn't publish one for this path.
+    async fn fetch_listing(&self, hash: &str) -> Result<Option<String>>;
+
+    /// Opens the NAR at `path` (as found in a narinfo's `URL:` field),
+    /// optionally restricted to `range`. Every backend here honors the range:
+    /// the file backend seeks and limits the read, and the HTTP backend fails
+    /// if the server doesn't answer with 206 Partial Content.
+    async fn open_nar(
+        &self,
+        path: &str,
+        range: Option<ByteRange>,
+    ) -> Result<Box<dyn AsyncRead + Send + Unpin>>;
+}
+
+/// Builds a [`StoreBackend`] from a store URI, dispatching on scheme:
+/// `https://`/`http://` for a binary cache fronted by HTTP, `s3://` for an
+/// S3-compatible object store, and `file://` for a local directory.
+pub async fn from_addr(addr: &str) -> Result<Box<dyn StoreBackend>> {
+    if let Some(rest) = addr.strip_prefix("s3://") {
+        Ok(Box::new(S3Backend::parse(rest).await?))
+    } else if let Some(path) = addr.strip_prefix("file://") {
+        Ok(Box::new(FileBackend::new(path)))
+    } else if addr.starts_with("http://") || addr.starts_with("https://") {
+        Ok(Box::new(HttpBackend::new(addr)))
+    } else {
+        Err(anyhow!("unsupported store URI scheme: {addr:?}"))
+    }
+}
+
+/// The original behavior: treats `store_uri` as an HTTP base and
+/// string-concatenates `.narinfo`/NAR paths onto it.
+pub struct HttpBackend {
+    base_url: String,
+    client: reqwest::Client,
+}
+
+impl HttpBackend {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl StoreBackend for HttpBackend {
+    async fn fetch_narinfo(&self, hash: &str) -> Result<String> {
+        let url = format!("{}/{}.narinfo", self.base_url, hash);
+        Ok(self.client.get(url).send().await?.text().await?)
+    }
+
+    async fn fetch_listing(&self, hash: &str) -> Result<Option<String>> {
+        let url = format!("{}/{}.ls", self.base_url, hash);
+        let resp = self.client.get(url).send().await?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(resp.error_for_status()?.text().await?))
+    }
+
+    async fn open_nar(
+        &self,
+        path: &str,
+        range: Option<ByteRange>,
+    ) -> Result<Box<dyn AsyncRead + Send + Unpin>> {
+        let url = format!("{}/{}", self.base_url, path);
+        let mut req = self.client.get(&url);
+        if let Some((start, end)) = range {
+            req = req.header(reqwest::header::RANGE, format!("bytes={start}-{end}"));
+        }
+
+        let resp = req.send().await?.error_for_status()?;
+
+        if range.is_some() && resp.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+            return Err(anyhow!(
+                "requested a Range but server returned {} instead of 206 Partial Content for {url}",
+                resp.status()
+            ));
+        }
+
+        let stream = resp.bytes_stream().map_err(|e| {
+            std::io::Error::new(std::io::ErrorKind::BrokenPipe, e.without_url().to_string())
+        });
+        Ok(Box::new(tokio_util::io::StreamReader::new(stream)))
+    }
+}
+
+/// Serves narinfos and NARs directly from a local directory, as produced by
+/// e.g. `nix copy --to file:///path`.
+pub struct FileBackend {
+    root: std::path::PathBuf,
+}
+
+impl FileBackend {
+    pub fn new(root: &str) -> Self {
+        Self {
+            root: std::path::PathBuf::from(root),
+        }
+    }
+}
+
+#[async_trait]
+impl StoreBackend for FileBackend {
+    async fn fetch_narinfo(&self, hash: &str) -> Result<String> {
+        let path = self.root.join(format!("{hash}.narinfo"));
+        Ok(tokio::fs::read_to_string(&path)
+            .await
+            .with_context(|| format!("reading {}", path.display()))?)
+    }
+
+    async fn fetch_listing(&self, hash: &str) -> Result<Option<String>> {
+        let path = self.root.join(format!("{hash}.ls"));
+        match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+
+    async fn open_nar(
+        &self,
+        path: &str,
+        range: Option<ByteRange>,
+    ) -> Result<Box<dyn AsyncRead + Send + Unpin>> {
+        use tokio::io::{AsyncSeekExt, AsyncReadExt};
+
+        let full_path = self.root.join(path);
+        let mut file = tokio::fs::File::open(&full_path)
+            .await
+            .with_context(|| format!("opening {}", full_path.display()))?;
+
+        if let Some((start, end)) = range {
+            file.seek(std::io::SeekFrom::Start(start)).await?;
+            return Ok(Box::new(file.take(end - start + 1)));
+        }
+
+        Ok(Box::new(file))
+    }
+}
+
+/// Serves narinfos and NARs from an S3-compatible object store.
+pub struct S3Backend {
+    bucket: String,
+    client: aws_sdk_s3::Client,
+}
+
+impl S3Backend {
+    /// Parses the part of an `s3://` URI after the scheme, e.g.
+    /// `my-cache?region=eu-west-1&endpoint=https://minio.example.com`.
+    pub async fn parse(rest: &str) -> Result<Self> {
+        let (bucket, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let mut region = None;
+        let mut endpoint = None;
+        for pair in query.split('&').filter(|s| !s.is_empty()) {
+            match pair.split_once('=') {
+                Some(("region", v)) => region = Some(v.to_string()),
+                Some(("endpoint", v)) => endpoint = Some(v.to_string()),
+                _ => {}
+            }
+        }
+
+        let mut loader = aws_config::defaults(aws_config::BehaviorVersion::latest());
+        if let Some(region) = region {
+            loader = loader.region(aws_sdk_s3::config::Region::new(region));
+        }
+        let mut conf_builder = aws_sdk_s3::config::Builder::from(&loader.load().await);
+        if let Some(endpoint) = endpoint {
+            conf_builder = conf_builder.endpoint_url(endpoint);
+        }
+
+        Ok(Self {
+            bucket: bucket.to_string(),
+            client: aws_sdk_s3::Client::from_conf(conf_builder.build()),
+        })
+    }
+
+    async fn get_object(
+        &self,
+        key: &str,
+        range: Option<ByteRange>,
+    ) -> Result<aws_sdk_s3::operation::get_object::GetObjectOutput> {
+        let mut req = self.client.get_object().bucket(&self.bucket).key(key);
+        if let Some((start, end)) = range {
+            req = req.range(format!("bytes={start}-{end}"));
+        }
+        Ok(req.send().await?)
+    }
+}
+
+#[async_trait]
+impl StoreBackend for S3Backend {
+    async fn fetch_narinfo(&self, hash: &str) -> Result<String> {
+        let obj = self.get_object(&format!("{hash}.narinfo"), None).await?;
+        let bytes = obj.body.collect().await?.into_bytes();
+        Ok(String::from_utf8(bytes.to_vec())?)
+    }
+
+    async fn fetch_listing(&self, hash: &str) -> Result<Option<String>> {
+        match self.get_object(&format!("{hash}.ls"), None).await {
+            Ok(obj) => {
+                let bytes = obj.body.collect().await?.into_bytes();
+                Ok(Some(String::from_utf8(bytes.to_vec())?))
+            }
+            Err(e) => {
+                if let Some(aws_sdk_s3::error::SdkError::ServiceError(se)) =
+                    e.downcast_ref::<aws_sdk_s3::error::SdkError<
+                        aws_sdk_s3::operation::get_object::GetObjectError,
+                    >>()
+                {
+                    if se.err().is_no_such_key() {
+                        return Ok(None);
+                    }
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn open_nar(
+        &self,
+        path: &str,
+        range: Option<ByteRange>,
+    ) -> Result<Box<dyn AsyncRead + Send + Unpin>> {
+        let obj = self.get_object(path, range).await?;
+        Ok(Box::new(obj.body.into_async_read()))
+    }
+}